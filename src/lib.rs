@@ -18,10 +18,12 @@
 //! fn main() {
 //! 	let io = IoHandler::new();
 //! 	io.add_method("say_hello", SayHello);
-//! 	let _server = Server::start(&"127.0.0.1:3030".parse().unwrap(), Arc::new(io), Some(AccessControlAllowOrigin::Null));
+//! 	let _server = Server::start(&"127.0.0.1:3030".parse().unwrap(), Arc::new(io), Some(AccessControlAllowOrigin::Null), None, DEFAULT_MAX_REQUEST_SIZE);
 //! }
 //! ```
 
+#[macro_use]
+extern crate log;
 extern crate hyper;
 extern crate unicase;
 extern crate jsonrpc_core as jsonrpc;
@@ -42,6 +44,56 @@ pub use hyper::header::AccessControlAllowOrigin;
 
 pub type ServerResult = Result<Server, RpcServerError>;
 
+/// Default upper bound on the accepted request body size (5 MiB).
+pub const DEFAULT_MAX_REQUEST_SIZE: usize = 5 * 1024 * 1024;
+
+/// Cross-origin access control policy.
+///
+/// Holds the list of origins that are allowed to talk to the server. Each entry
+/// is a `AccessControlAllowOrigin`: an exact `Value(..)`, the `Null` origin, or
+/// the `Any` wildcard (`*`). The matching origin from the incoming request is
+/// reflected back in `Access-Control-Allow-Origin`; an empty policy disables
+/// CORS entirely.
+#[derive(Debug, Clone)]
+pub struct AccessControl {
+	allowed_origins: Vec<AccessControlAllowOrigin>,
+}
+
+impl AccessControl {
+	/// Resolve the `Access-Control-Allow-Origin` value to echo for a given
+	/// request `Origin`, or `None` when the origin is not permitted.
+	fn allow_origin(&self, origin: Option<&str>) -> Option<AccessControlAllowOrigin> {
+		let origin = match origin {
+			Some(origin) => origin,
+			None => return None,
+		};
+		for entry in &self.allowed_origins {
+			match *entry {
+				AccessControlAllowOrigin::Any =>
+					return Some(AccessControlAllowOrigin::Value(origin.to_owned())),
+				AccessControlAllowOrigin::Null if origin == "null" =>
+					return Some(AccessControlAllowOrigin::Null),
+				AccessControlAllowOrigin::Value(ref value) if value == origin =>
+					return Some(AccessControlAllowOrigin::Value(origin.to_owned())),
+				_ => {},
+			}
+		}
+		None
+	}
+}
+
+impl From<Option<AccessControlAllowOrigin>> for AccessControl {
+	fn from(cors_domain: Option<AccessControlAllowOrigin>) -> Self {
+		AccessControl { allowed_origins: cors_domain.into_iter().collect() }
+	}
+}
+
+impl From<Vec<AccessControlAllowOrigin>> for AccessControl {
+	fn from(allowed_origins: Vec<AccessControlAllowOrigin>) -> Self {
+		AccessControl { allowed_origins: allowed_origins }
+	}
+}
+
 /// RPC Server startup error
 #[derive(Debug)]
 pub enum RpcServerError {
@@ -58,6 +110,35 @@ impl From<hyper::error::Error> for RpcServerError {
 	}
 }
 
+/// HTTP request metadata passed to `HandleRequest::handle_request_with_meta`.
+///
+/// Carries the raw request headers and the peer `SocketAddr` of a POST request.
+#[derive(Debug, Clone)]
+pub struct Metadata {
+	/// Headers of the originating HTTP request.
+	pub headers: Headers,
+	/// Address of the remote peer, when known.
+	pub peer_addr: Option<SocketAddr>,
+}
+
+/// Dispatch a JSON-RPC request carrying the originating HTTP [`Metadata`].
+///
+/// `jsonrpc::IoHandler` has no notion of transport metadata, so the meta-aware
+/// entry point is added to it here as an extension trait. The implementation
+/// for `IoHandler` forwards to `handle_request` and discards the metadata.
+/// `Server` and `ServerHandler` dispatch through `Arc<IoHandler>` only, so the
+/// metadata does not reach the JSON-RPC methods.
+pub trait HandleRequest {
+	/// Handle `request`, with the captured HTTP `meta` of the connection.
+	fn handle_request_with_meta(&self, request: &str, meta: Metadata) -> Option<String>;
+}
+
+impl HandleRequest for IoHandler {
+	fn handle_request_with_meta(&self, request: &str, _meta: Metadata) -> Option<String> {
+		self.handle_request(request)
+	}
+}
+
 /// PanicHandling function
 pub struct PanicHandler {
 	pub handler: Arc<Mutex<Option<Box<Fn() -> () + Send + 'static>>>>
@@ -67,10 +148,17 @@ pub struct PanicHandler {
 pub struct ServerHandler {
 	panic_handler: PanicHandler,
 	jsonrpc_handler: Arc<IoHandler>,
-	cors_domain: Option<AccessControlAllowOrigin>,
+	access_control: AccessControl,
+	allowed_hosts: Option<Vec<String>>,
+	origin: Option<String>,
+	peer_addr: Option<SocketAddr>,
+	metadata: Option<Metadata>,
+	max_request_size: usize,
 	request: String,
 	response: Option<String>,
 	write_pos: usize,
+	host_allowed: bool,
+	request_too_large: bool,
 }
 
 impl Drop for ServerHandler {
@@ -86,14 +174,50 @@ impl Drop for ServerHandler {
 
 impl ServerHandler {
 	/// Create new request handler.
-	pub fn new(jsonrpc_handler: Arc<IoHandler>, cors_domain: Option<AccessControlAllowOrigin>, panic_handler: PanicHandler) -> Self {
+	pub fn new(jsonrpc_handler: Arc<IoHandler>, access_control: AccessControl, allowed_hosts: Option<Vec<String>>, max_request_size: usize, panic_handler: PanicHandler) -> Self {
 		ServerHandler {
 			panic_handler: panic_handler,
 			jsonrpc_handler: jsonrpc_handler,
-			cors_domain: cors_domain,
+			access_control: access_control,
+			allowed_hosts: allowed_hosts,
+			origin: None,
+			peer_addr: None,
+			metadata: None,
+			max_request_size: max_request_size,
 			request: String::new(),
 			response: None,
 			write_pos: 0,
+			host_allowed: true,
+			request_too_large: false,
+		}
+	}
+
+	/// Check the request `Host` header against the configured allowlist.
+	///
+	/// A `None` allowlist accepts every host. Otherwise the header's hostname is
+	/// compared case-insensitively against each entry, matching the `:port` only
+	/// when the entry carries one. A missing `Host` header is rejected.
+	fn is_host_allowed(&self, request: &Request) -> bool {
+		match self.allowed_hosts {
+			None => true,
+			Some(ref allowed) => {
+				let host = match request.headers().get::<hyper::header::Host>() {
+					Some(host) => host,
+					None => return false,
+				};
+				let hostname = host.hostname.to_lowercase();
+				allowed.iter().any(|entry| {
+					let entry = entry.to_lowercase();
+					if entry.contains(':') {
+						match host.port {
+							Some(port) => entry == format!("{}:{}", hostname, port),
+							None => entry == hostname,
+						}
+					} else {
+						entry == hostname
+					}
+				})
+			}
 		}
 	}
 
@@ -113,8 +237,11 @@ impl ServerHandler {
 			])
 		);
 
-		if let Some(ref cors_domain) = self.cors_domain {
-			headers.set(cors_domain.clone());
+		match self.access_control.allow_origin(self.origin.as_ref().map(|o| o.as_str())) {
+			Some(origin) => headers.set(origin),
+			None => if self.origin.is_some() {
+				trace!("Origin {:?} from {:?} is not allowed, omitting CORS header", self.origin, self.peer_addr);
+			},
 		}
 		headers
 	}
@@ -122,21 +249,52 @@ impl ServerHandler {
 
 impl hyper::server::Handler<HttpStream> for ServerHandler {
 	fn on_request(&mut self, request: Request) -> Next {
+		self.peer_addr = Some(request.remote_addr());
+		if !self.is_host_allowed(&request) {
+			warn!("Rejected request with disallowed Host header from {:?}", self.peer_addr);
+			self.host_allowed = false;
+			self.response = None;
+			return Next::write();
+		}
+		self.origin = request.headers().get_raw("origin")
+			.and_then(|raw| raw.first())
+			.and_then(|value| String::from_utf8(value.clone()).ok());
 		match *request.method() {
 			Method::Options => {
 				self.response = Some(String::new());
 				Next::write()
 			},
-			Method::Post => Next::read(),
+			Method::Post => {
+				self.metadata = Some(Metadata {
+					headers: request.headers().clone(),
+					peer_addr: self.peer_addr,
+				});
+				Next::read()
+			},
 			_ => Next::write(),
 		}
 	}
 
 	/// This event occurs each time the `Request` is ready to be read from.
 	fn on_request_readable(&mut self, decoder: &mut Decoder<HttpStream>) -> Next {
-		match decoder.read_to_string(&mut self.request) {
+		let read = decoder.read_to_string(&mut self.request);
+		// `read_to_string` appends on every readable event and only returns
+		// `Ok(0)` at EOF, so the size guard has to run after each append -
+		// including the `WouldBlock` path - to reject a slow POST before the
+		// whole body is buffered.
+		if self.request.len() > self.max_request_size {
+			warn!("Rejected oversized request ({} bytes) from {:?}", self.request.len(), self.peer_addr);
+			self.request_too_large = true;
+			self.request = String::new();
+			self.response = Some(String::new());
+			return Next::write();
+		}
+		match read {
 			Ok(0) => {
-				self.response = self.jsonrpc_handler.handle_request(&self.request);
+				self.response = match self.metadata.take() {
+					Some(meta) => self.jsonrpc_handler.handle_request_with_meta(&self.request, meta),
+					None => self.jsonrpc_handler.handle_request(&self.request),
+				};
 				match self.response {
 					Some(ref mut r) => r.push('\n'),
 					_ => ()
@@ -149,7 +307,7 @@ impl hyper::server::Handler<HttpStream> for ServerHandler {
 			Err(e) => match e.kind() {
 				::std::io::ErrorKind::WouldBlock => Next::read(),
 				_ => {
-					//trace!("Read error: {}", e);
+					warn!("Read error from {:?}: {}", self.peer_addr, e);
 					Next::end()
 				}
 			}
@@ -159,7 +317,15 @@ impl hyper::server::Handler<HttpStream> for ServerHandler {
 		/// This event occurs after the first time this handled signals `Next::write()`.
 	fn on_response(&mut self, response: &mut Response) -> Next {
 		*response.headers_mut() = self.response_headers();
-		if self.response.is_none() {
+		if !self.host_allowed {
+			response.set_status(hyper::status::StatusCode::Forbidden);
+		} else if self.request_too_large {
+			// Oversize bodies are a transport-level condition, so we answer with
+			// a bare 413 and an empty body rather than a JSON-RPC error object -
+			// the request never parsed as JSON-RPC, so there is no id to echo.
+			response.set_status(hyper::status::StatusCode::PayloadTooLarge);
+		} else if self.response.is_none() {
+			trace!("No response for request from {:?}, returning 405", self.peer_addr);
 			response.set_status(hyper::status::StatusCode::MethodNotAllowed);
 		}
 		Next::write()
@@ -183,7 +349,7 @@ impl hyper::server::Handler<HttpStream> for ServerHandler {
 					Err(e) => match e.kind() {
 						::std::io::ErrorKind::WouldBlock => Next::write(),
 						_ => {
-							//trace!("Write error: {}", e);
+							warn!("Write error to {:?}: {}", self.peer_addr, e);
 							Next::end()
 						}
 					}
@@ -215,36 +381,78 @@ impl hyper::server::Handler<HttpStream> for ServerHandler {
 /// fn main() {
 /// 	let io = IoHandler::new();
 /// 	io.add_method("say_hello", SayHello);
-/// 	let _server = Server::start(&"127.0.0.1:3030".parse().unwrap(), Arc::new(io), Some(AccessControlAllowOrigin::Null));
+/// 	let _server = Server::start(&"127.0.0.1:3030".parse().unwrap(), Arc::new(io), Some(AccessControlAllowOrigin::Null), None, DEFAULT_MAX_REQUEST_SIZE);
 /// }
 /// ```
+///
+/// The server binds the address once and lets hyper drive the accept loop on a
+/// pool of worker threads (see `start_with_threads`); it owns the resulting
+/// single `Listening` handle and closes it on `Drop`. Binding one socket and
+/// fanning out worker threads is preferred over binding one socket per thread,
+/// which would need `SO_REUSEPORT` and is not portable on this hyper version.
 pub struct Server {
 	server: Option<hyper::server::Listening>,
 	panic_handler: Arc<Mutex<Option<Box<Fn() -> () + Send>>>>
 }
 
 impl Server {
-	pub fn start(addr: &SocketAddr, jsonrpc_handler: Arc<IoHandler>, cors_domain: Option<AccessControlAllowOrigin>) -> ServerResult {
+	pub fn start<A>(addr: &SocketAddr, jsonrpc_handler: Arc<IoHandler>, access_control: A, allowed_hosts: Option<Vec<String>>, max_request_size: usize) -> ServerResult
+		where A: Into<AccessControl> {
+		Server::start_with_threads(addr, jsonrpc_handler, access_control, allowed_hosts, max_request_size, 1)
+	}
+
+	/// Start the server with `num_threads` worker threads accepting on a single
+	/// bound listener, all sharing the same `IoHandler`, access policy and panic
+	/// handler.
+	///
+	/// The address is bound once and hyper's own worker pool drives the accept
+	/// loop, so `num_threads` greater than one adds throughput without a second
+	/// `bind` on the same address. A `num_threads` of `0` is clamped to `1`.
+	/// `start` is the single threaded case (`num_threads == 1`).
+	pub fn start_with_threads<A>(addr: &SocketAddr, jsonrpc_handler: Arc<IoHandler>, access_control: A, allowed_hosts: Option<Vec<String>>, max_request_size: usize, num_threads: usize) -> ServerResult
+		where A: Into<AccessControl> {
+		let access_control = access_control.into();
+		let num_threads = ::std::cmp::max(num_threads, 1);
 		let panic_handler = Arc::new(Mutex::new(None));
 		let panic_for_server = panic_handler.clone();
-		let srv = try!(try!(hyper::Server::http(addr)).handle(move |_| {
+		let srv = try!(try!(hyper::Server::http(addr)).handle_threads(move |_| {
 			let handler = PanicHandler { handler: panic_for_server.clone() };
-			ServerHandler::new(jsonrpc_handler.clone(), cors_domain.clone(), handler)
-		}));
+			ServerHandler::new(jsonrpc_handler.clone(), access_control.clone(), allowed_hosts.clone(), max_request_size, handler)
+		}, num_threads));
 		Ok(Server {
 			server: Some(srv),
 			panic_handler: panic_handler,
 		})
 	}
-	
-	pub fn set_panic_handler<F>(&self, handler: F) 
+
+	pub fn set_panic_handler<F>(&self, handler: F)
 		where F : Fn() -> () + Send + 'static {
 		*self.panic_handler.lock().unwrap() = Some(Box::new(handler));
 	}
+
+	/// Shut the server down, consuming it and reporting any error that the
+	/// underlying listener raised while closing.
+	pub fn close(mut self) -> Result<(), RpcServerError> {
+		match self.server.take() {
+			Some(mut srv) => srv.close().map_err(Into::into),
+			None => Ok(()),
+		}
+	}
+
+	/// Block the calling thread until the listener stops accepting connections.
+	pub fn wait(mut self) {
+		if let Some(mut srv) = self.server.take() {
+			srv.await();
+		}
+	}
 }
 
 impl Drop for Server {
 	fn drop(&mut self) {
-		self.server.take().unwrap().close()
+		if let Some(mut srv) = self.server.take() {
+			if let Err(e) = srv.close() {
+				warn!("Error closing RPC server listener: {}", e);
+			}
+		}
 	}
 }